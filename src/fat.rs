@@ -0,0 +1,241 @@
+//! Support for fat (a.k.a. universal) mach-o files, which bundle together
+//! thin mach-o files for several architectures.
+
+use mach_o_sys::fat;
+
+use {Error, Header};
+
+// `mach_o_sys` only knows about the 32-bit fat magic; the 64-bit variant
+// (used when an architecture's slice would not fit in a 32-bit offset) isn't
+// bound there yet.
+const FAT_MAGIC_64: u32 = 0xcafebabf;
+const FAT_CIGAM_64: u32 = 0xbfbafeca;
+
+fn read_u32_be(bytes: &[u8]) -> u32 {
+    ((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8) |
+    (bytes[3] as u32)
+}
+
+fn read_u64_be(bytes: &[u8]) -> u64 {
+    ((read_u32_be(&bytes[0..4]) as u64) << 32) | (read_u32_be(&bytes[4..8]) as u64)
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Width {
+    Fat32,
+    Fat64,
+}
+
+impl Width {
+    // The size in bytes of a single `fat_arch`/`fat_arch_64` record.
+    fn arch_size(&self) -> usize {
+        match *self {
+            Width::Fat32 => 20,
+            Width::Fat64 => 32,
+        }
+    }
+}
+
+/// A fat (universal) mach-o file: a header listing the architectures it
+/// contains, followed by a thin mach-o file for each one.
+#[derive(Copy, Clone, Debug)]
+pub struct FatFile<'a> {
+    input: &'a [u8],
+    nfat_arch: u32,
+    width: Width,
+}
+
+impl<'a> FatFile<'a> {
+    /// Parse the fat file header from the given input slice.
+    pub fn new(input: &'a [u8]) -> Result<FatFile<'a>, Error> {
+        if input.len() < 8 {
+            return Err(Error::InputNotLongEnough);
+        }
+
+        let magic = read_u32_be(&input[0..4]);
+        let width = match magic {
+            fat::FAT_MAGIC | fat::FAT_CIGAM => Width::Fat32,
+            FAT_MAGIC_64 | FAT_CIGAM_64 => Width::Fat64,
+            _ => return Err(Error::UnknownMagicHeaderValue),
+        };
+
+        let nfat_arch = read_u32_be(&input[4..8]);
+        let archs_end = 8 + nfat_arch as usize * width.arch_size();
+        if input.len() < archs_end {
+            return Err(Error::InputNotLongEnough);
+        }
+
+        Ok(FatFile {
+            input: input,
+            nfat_arch: nfat_arch,
+            width: width,
+        })
+    }
+
+    /// Iterate over the architectures contained in this fat file.
+    pub fn archs(&self) -> FatArchs<'a> {
+        FatArchs {
+            input: self.input,
+            index: 0,
+            count: self.nfat_arch,
+            width: self.width,
+        }
+    }
+}
+
+/// An iterator over the architectures in a `FatFile`.
+#[derive(Copy, Clone, Debug)]
+pub struct FatArchs<'a> {
+    input: &'a [u8],
+    index: u32,
+    count: u32,
+    width: Width,
+}
+
+impl<'a> Iterator for FatArchs<'a> {
+    type Item = FatArch<'a>;
+
+    fn next(&mut self) -> Option<FatArch<'a>> {
+        if self.index >= self.count {
+            return None;
+        }
+
+        let start = 8 + self.index as usize * self.width.arch_size();
+        let record = &self.input[start..start + self.width.arch_size()];
+
+        let cputype = read_u32_be(&record[0..4]) as i32;
+        let cpusubtype = read_u32_be(&record[4..8]) as i32;
+        let (offset, size, align) = match self.width {
+            Width::Fat32 => {
+                (read_u32_be(&record[8..12]) as u64,
+                 read_u32_be(&record[12..16]) as u64,
+                 read_u32_be(&record[16..20]))
+            }
+            Width::Fat64 => {
+                (read_u64_be(&record[8..16]),
+                 read_u64_be(&record[16..24]),
+                 read_u32_be(&record[24..28]))
+            }
+        };
+
+        self.index += 1;
+
+        Some(FatArch {
+            input: self.input,
+            cputype: cputype,
+            cpusubtype: cpusubtype,
+            offset: offset,
+            size: size,
+            align: align,
+        })
+    }
+}
+
+/// A single architecture's slice within a `FatFile`.
+#[derive(Copy, Clone, Debug)]
+pub struct FatArch<'a> {
+    input: &'a [u8],
+    cputype: i32,
+    cpusubtype: i32,
+    offset: u64,
+    size: u64,
+    align: u32,
+}
+
+impl<'a> FatArch<'a> {
+    /// This architecture's CPU type, e.g. `CPU_TYPE_X86_64`.
+    pub fn cputype(&self) -> i32 {
+        self.cputype
+    }
+
+    /// This architecture's CPU subtype.
+    pub fn cpusubtype(&self) -> i32 {
+        self.cpusubtype
+    }
+
+    /// The alignment, as a power of two, of this architecture's slice within
+    /// the fat file.
+    pub fn align(&self) -> u32 {
+        self.align
+    }
+
+    /// Get the thin mach-o header for this architecture's slice.
+    pub fn header(&self) -> Result<Header<'a>, Error> {
+        let start = self.offset as usize;
+        let end = start.checked_add(self.size as usize);
+        let slice = end.and_then(|end| self.input.get(start..end));
+        match slice {
+            Some(slice) => Header::new(slice),
+            None => Err(Error::InputNotLongEnough),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mach_o_sys::loader;
+
+    // A fat header (big-endian, `FAT_MAGIC`) with one `fat_arch` record
+    // pointing at a thin, little-endian 64-bit mach-o header placed right
+    // after it.
+    const FAT32_ONE_ARCH: [u8; 28 + 32] =
+        [// fat_header: magic, nfat_arch
+         0xca, 0xfe, 0xba, 0xbe, 0x00, 0x00, 0x00, 0x01,
+         // fat_arch: cputype, cpusubtype, offset, size, align
+         0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x00, 0x00,
+         0x20, 0x00, 0x00, 0x00, 0x00,
+         // thin mach_header_64 at offset 28, copied from lib.rs's
+         // `LITTLE_ENDIAN_HEADER_64`.
+         0xcf, 0xfa, 0xed, 0xfe, 0x07, 0x00, 0x00, 0x01, 0x03, 0x00, 0x00, 0x80, 0x02, 0x00, 0x00,
+         0x00, 0x12, 0x00, 0x00, 0x00, 0xd8, 0x08, 0x00, 0x00, 0x85, 0x80, 0xa1, 0x00, 0x00, 0x00,
+         0x00, 0x00];
+
+    #[test]
+    fn test_read_fat32_one_arch() {
+        let file = FatFile::new(&FAT32_ONE_ARCH).expect("Should parse the fat header OK");
+        let archs: Vec<_> = file.archs().collect();
+        assert_eq!(archs.len(), 1);
+
+        let arch = &archs[0];
+        assert_eq!(arch.cputype(), 7);
+        assert_eq!(arch.cpusubtype(), 3);
+        assert_eq!(arch.align(), 0);
+
+        let header = arch.header().expect("Should parse the thin header OK");
+        assert_eq!(header.magic(), loader::MH_MAGIC_64);
+    }
+
+    #[test]
+    fn test_unknown_magic() {
+        let mut input = FAT32_ONE_ARCH;
+        input[0] = 0;
+        assert_eq!(FatFile::new(&input).unwrap_err(), Error::UnknownMagicHeaderValue);
+    }
+
+    #[test]
+    fn test_truncated_archs() {
+        // Long enough for the fat header, but not for the one `fat_arch`
+        // record it claims to have.
+        assert_eq!(FatFile::new(&FAT32_ONE_ARCH[..10]).unwrap_err(),
+                   Error::InputNotLongEnough);
+    }
+
+    // A fat64 header (`FAT_MAGIC_64`) with one `fat_arch_64` record whose
+    // `offset`/`size` overflow when added together.
+    const FAT64_OVERFLOWING_ARCH: [u8; 8 + 32] =
+        [// fat_header: magic, nfat_arch
+         0xca, 0xfe, 0xba, 0xbf, 0x00, 0x00, 0x00, 0x01,
+         // fat_arch_64: cputype, cpusubtype, offset = u64::MAX - 5, size = 10,
+         // align, reserved
+         0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x03, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+         0xfa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+         0x00, 0x00];
+
+    #[test]
+    fn test_overflowing_arch_offset_does_not_panic() {
+        let file = FatFile::new(&FAT64_OVERFLOWING_ARCH).expect("Should parse the fat header OK");
+        let arch = file.archs().next().expect("Should have one arch");
+        assert_eq!(arch.header().unwrap_err(), Error::InputNotLongEnough);
+    }
+}
@@ -2,12 +2,25 @@
 
 #![deny(missing_docs)]
 
+#[macro_use]
+extern crate bitflags;
+extern crate flate2;
 extern crate mach_o_sys;
 
-use mach_o_sys::{loader, getsect};
+use mach_o_sys::loader;
 use std::ffi::CStr;
 use std::mem;
 
+pub mod fat;
+pub mod segment;
+pub mod symbol;
+pub mod write;
+
+use segment::{LoadCommand, LoadCommands, Section, Segments};
+use symbol::Symbols;
+
+const LC_SYMTAB: u32 = loader::LC_SYMTAB as u32;
+
 /// An error that occurred while parsing the mach-o file contents.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Error {
@@ -18,16 +31,211 @@ pub enum Error {
 }
 
 #[derive(Copy, Clone, Debug)]
-enum RawHeader {
-    MachHeader32(*const loader::mach_header),
-    MachHeader64(*const loader::mach_header_64),
+enum Bitness {
+    Bits32,
+    Bits64,
+}
+
+// The high bit of `cputype` that marks the 64-bit variant of a 32-bit
+// architecture, e.g. `CPU_TYPE_X86_64 == CPU_TYPE_X86 | CPU_ARCH_ABI64`.
+// `mach_o_sys` doesn't bind `mach/machine.h`, so these live here instead.
+pub(crate) const CPU_ARCH_ABI64: i32 = 0x01000000;
+const CPU_TYPE_X86: i32 = 7;
+const CPU_TYPE_ARM: i32 = 12;
+const CPU_TYPE_POWERPC: i32 = 18;
+
+/// A CPU architecture, decoded from a header's `cputype` field.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CpuType {
+    /// 32-bit x86.
+    X86,
+    /// 64-bit x86 (a.k.a. x86_64 or amd64).
+    X86_64,
+    /// 32-bit ARM.
+    Arm,
+    /// 64-bit ARM.
+    Arm64,
+    /// 32-bit PowerPC.
+    PowerPc,
+    /// Some other, unrecognized CPU type.
+    Other(i32),
+}
+
+impl CpuType {
+    fn from_raw(cputype: i32) -> CpuType {
+        match cputype {
+            CPU_TYPE_X86 => CpuType::X86,
+            CPU_TYPE_ARM => CpuType::Arm,
+            CPU_TYPE_POWERPC => CpuType::PowerPc,
+            t if t == CPU_TYPE_X86 | CPU_ARCH_ABI64 => CpuType::X86_64,
+            t if t == CPU_TYPE_ARM | CPU_ARCH_ABI64 => CpuType::Arm64,
+            other => CpuType::Other(other),
+        }
+    }
+}
+
+/// What kind of mach-o file this is, decoded from a header's `filetype`
+/// field (the `MH_*` constants).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FileType {
+    /// A relocatable object file (`MH_OBJECT`).
+    Object,
+    /// A demand-paged executable file (`MH_EXECUTE`).
+    Execute,
+    /// A fixed VM shared library file (`MH_FVMLIB`).
+    Fvmlib,
+    /// A core file (`MH_CORE`).
+    Core,
+    /// A preloaded executable file (`MH_PRELOAD`).
+    Preload,
+    /// A dynamically bound shared library (`MH_DYLIB`).
+    Dylib,
+    /// The dynamic link editor (`MH_DYLINKER`).
+    Dylinker,
+    /// A dynamically bound bundle file (`MH_BUNDLE`).
+    Bundle,
+    /// A shared library stub for static linking, with no section contents
+    /// (`MH_DYLIB_STUB`).
+    DylibStub,
+    /// A companion file with only debug sections (`MH_DSYM`).
+    Dsym,
+    /// A kext bundle (`MH_KEXT_BUNDLE`).
+    KextBundle,
+    /// Some other, unrecognized file type.
+    Other(u32),
+}
+
+impl FileType {
+    fn from_raw(filetype: u32) -> FileType {
+        match filetype {
+            v if v == loader::MH_OBJECT as u32 => FileType::Object,
+            v if v == loader::MH_EXECUTE as u32 => FileType::Execute,
+            v if v == loader::MH_FVMLIB as u32 => FileType::Fvmlib,
+            v if v == loader::MH_CORE as u32 => FileType::Core,
+            v if v == loader::MH_PRELOAD as u32 => FileType::Preload,
+            v if v == loader::MH_DYLIB as u32 => FileType::Dylib,
+            v if v == loader::MH_DYLINKER as u32 => FileType::Dylinker,
+            v if v == loader::MH_BUNDLE as u32 => FileType::Bundle,
+            v if v == loader::MH_DYLIB_STUB as u32 => FileType::DylibStub,
+            v if v == loader::MH_DSYM as u32 => FileType::Dsym,
+            v if v == loader::MH_KEXT_BUNDLE as u32 => FileType::KextBundle,
+            other => FileType::Other(other),
+        }
+    }
+
+    pub(crate) fn to_raw(self) -> u32 {
+        match self {
+            FileType::Object => loader::MH_OBJECT as u32,
+            FileType::Execute => loader::MH_EXECUTE as u32,
+            FileType::Fvmlib => loader::MH_FVMLIB as u32,
+            FileType::Core => loader::MH_CORE as u32,
+            FileType::Preload => loader::MH_PRELOAD as u32,
+            FileType::Dylib => loader::MH_DYLIB as u32,
+            FileType::Dylinker => loader::MH_DYLINKER as u32,
+            FileType::Bundle => loader::MH_BUNDLE as u32,
+            FileType::DylibStub => loader::MH_DYLIB_STUB as u32,
+            FileType::Dsym => loader::MH_DSYM as u32,
+            FileType::KextBundle => loader::MH_KEXT_BUNDLE as u32,
+            FileType::Other(v) => v,
+        }
+    }
+}
+
+bitflags! {
+    /// Flags from a mach-o header's `flags` field (the `MH_*` constants).
+    pub struct Flags: u32 {
+        /// The object file has no undefined references.
+        const NOUNDEFS = loader::MH_NOUNDEFS as u32;
+        /// The object file is the output of an incremental link.
+        const INCRLINK = loader::MH_INCRLINK as u32;
+        /// The object file's undefined references are resolved by the
+        /// dynamic linker.
+        const DYLDLINK = loader::MH_DYLDLINK as u32;
+        /// The object file's undefined references are bound by the dynamic
+        /// linker when loaded.
+        const BINDATLOAD = loader::MH_BINDATLOAD as u32;
+        /// The file's undefined references are prebound.
+        const PREBOUND = loader::MH_PREBOUND as u32;
+        /// The file has its read-only and read-write segments split.
+        const SPLIT_SEGS = loader::MH_SPLIT_SEGS as u32;
+        /// The shared library init routine is to be run lazily.
+        const LAZY_INIT = loader::MH_LAZY_INIT as u32;
+        /// The image is using two-level namespace bindings.
+        const TWOLEVEL = loader::MH_TWOLEVEL as u32;
+        /// The executable is forcing all images to use flat namespace
+        /// bindings.
+        const FORCE_FLAT = loader::MH_FORCE_FLAT as u32;
+        /// This umbrella guarantees no multiple definitions of symbols in
+        /// its sub-images.
+        const NOMULTIDEFS = loader::MH_NOMULTIDEFS as u32;
+        /// Do not have the dynamic linker notify the prebinding agent about
+        /// this executable.
+        const NOFIXPREBINDING = loader::MH_NOFIXPREBINDING as u32;
+        /// The binary is not prebound but can have its prebinding
+        /// redone.
+        const PREBINDABLE = loader::MH_PREBINDABLE as u32;
+        /// Indicates that this binary binds to all two-level namespace
+        /// modules of its dependent libraries.
+        const ALLMODSBOUND = loader::MH_ALLMODSBOUND as u32;
+        /// Safe to divide up the sections into sub-sections via symbols for
+        /// dead code stripping.
+        const SUBSECTIONS_VIA_SYMBOLS = loader::MH_SUBSECTIONS_VIA_SYMBOLS as u32;
+        /// The binary has been canonicalized via the unprebind operation.
+        const CANONICAL = loader::MH_CANONICAL as u32;
+        /// The final linked image contains external weak symbols.
+        const WEAK_DEFINES = loader::MH_WEAK_DEFINES as u32;
+        /// The final linked image uses weak symbols.
+        const BINDS_TO_WEAK = loader::MH_BINDS_TO_WEAK;
+        /// The image is allowed to have its stack be executable.
+        const ALLOW_STACK_EXECUTION = loader::MH_ALLOW_STACK_EXECUTION;
+        /// When this bit is set, the binary declares it is safe for use in
+        /// processes with uid zero.
+        const ROOT_SAFE = loader::MH_ROOT_SAFE;
+        /// When this bit is set, the binary declares it is safe for use in
+        /// processes when issetugid() is true.
+        const SETUID_SAFE = loader::MH_SETUID_SAFE;
+        /// When this bit is set on a dylib, the static linker does not need
+        /// to examine dependent dylibs to see if any are re-exported.
+        const NO_REEXPORTED_DYLIBS = loader::MH_NO_REEXPORTED_DYLIBS;
+        /// When this bit is set, the OS will load the main executable at a
+        /// random address, a.k.a. position-independent executable.
+        const PIE = loader::MH_PIE;
+        /// The static linker does not need to examine dependent dylibs to
+        /// see if it is safe to strip this dylib.
+        const DEAD_STRIPPABLE_DYLIB = loader::MH_DEAD_STRIPPABLE_DYLIB;
+        /// This binary has thread local variables.
+        const HAS_TLV_DESCRIPTORS = loader::MH_HAS_TLV_DESCRIPTORS;
+        /// The OS runs the main executable with a non-executable heap.
+        const NO_HEAP_EXECUTION = loader::MH_NO_HEAP_EXECUTION;
+        /// The code was linked for use in an application extension.
+        const APP_EXTENSION_SAFE = loader::MH_APP_EXTENSION_SAFE;
+    }
+}
+
+// Read a native-endian `u32` out of `bytes` and, if `swap` is set, flip it to
+// the other byte order. `swap` is set whenever the mach-o file's magic is one
+// of the `*_CIGAM` values, meaning its multi-byte fields were written in the
+// non-host byte order.
+pub(crate) fn read_u32(bytes: &[u8], swap: bool) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(bytes);
+    let value = u32::from_ne_bytes(buf);
+    if swap { value.swap_bytes() } else { value }
+}
+
+pub(crate) fn read_u64(bytes: &[u8], swap: bool) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(bytes);
+    let value = u64::from_ne_bytes(buf);
+    if swap { value.swap_bytes() } else { value }
 }
 
 /// A mach-o file header.
 #[derive(Copy, Clone, Debug)]
 pub struct Header<'a> {
-    raw_header: RawHeader,
     input: &'a [u8],
+    bitness: Bitness,
+    swap: bool,
 }
 
 impl<'a> Header<'a> {
@@ -37,176 +245,132 @@ impl<'a> Header<'a> {
             return Err(Error::InputNotLongEnough);
         }
 
-        let mut magic: [u8; 4] = [0, 0, 0, 0];
-        magic.copy_from_slice(&input[..4]);
-        let magic = unsafe { mem::transmute(magic) };
+        let magic = read_u32(&input[..4], false);
 
-        match magic {
+        let (bitness, swap) = match magic {
             // 32 bit.
-            loader::MH_MAGIC | loader::MH_CIGAM => {
-                Ok(Header {
-                    raw_header: RawHeader::MachHeader32(unsafe { mem::transmute(input.as_ptr()) }),
-                    input: input,
-                })
-            }
+            loader::MH_MAGIC => (Bitness::Bits32, false),
+            loader::MH_CIGAM => (Bitness::Bits32, true),
 
             // 64 bit.
-            loader::MH_MAGIC_64 |
-            loader::MH_CIGAM_64 => {
-                if input.len() < mem::size_of::<loader::mach_header_64>() {
-                    return Err(Error::InputNotLongEnough);
-                }
+            loader::MH_MAGIC_64 => (Bitness::Bits64, false),
+            loader::MH_CIGAM_64 => (Bitness::Bits64, true),
+
+            // Unknown magic header value.
+            _ => return Err(Error::UnknownMagicHeaderValue),
+        };
 
-                Ok(Header {
-                    raw_header: RawHeader::MachHeader64(unsafe { mem::transmute(input.as_ptr()) }),
-                    input: input,
-                })
+        if let Bitness::Bits64 = bitness {
+            if input.len() < mem::size_of::<loader::mach_header_64>() {
+                return Err(Error::InputNotLongEnough);
             }
+        }
 
-            // Unknown magic header value.
-            _ => Err(Error::UnknownMagicHeaderValue),
+        Ok(Header {
+            input: input,
+            bitness: bitness,
+            swap: swap,
+        })
+    }
+
+    fn header_size(&self) -> usize {
+        match self.bitness {
+            Bitness::Bits32 => mem::size_of::<loader::mach_header>(),
+            Bitness::Bits64 => mem::size_of::<loader::mach_header_64>(),
         }
     }
 
+    fn read_u32(&self, offset: usize) -> u32 {
+        read_u32(&self.input[offset..offset + 4], self.swap)
+    }
+
     /// Get the magic value for this header.
     pub fn magic(&self) -> u32 {
-        unsafe {
-            match self.raw_header {
-                RawHeader::MachHeader32(h) => h.as_ref().unwrap().magic,
-                RawHeader::MachHeader64(h) => h.as_ref().unwrap().magic,
-            }
-        }
+        self.read_u32(0)
     }
 
-    /// Get the data for a given section, if it exists.
-    pub fn get_section(&self, segment_name: &CStr, section_name: &CStr) -> Option<Section<'a>> {
-        unsafe {
-            match self.raw_header {
-                RawHeader::MachHeader32(h) => {
-                    let h: *mut getsect::mach_header = mem::transmute(h);
-                    let section = if self.magic() == loader::MH_MAGIC {
-                        getsect::getsectbynamefromheader(h,
-                                                         segment_name.as_ptr(),
-                                                         section_name.as_ptr())
-                    } else {
-                        assert_eq!(self.magic(), loader::MH_CIGAM);
-                        getsect::getsectbynamefromheaderwithswap(h,
-                                                                 segment_name.as_ptr(),
-                                                                 section_name.as_ptr(),
-                                                                 1)
-                    };
-
-                    match section.as_ref() {
-                        None => None,
-                        Some(section) => {
-                            Some(Section {
-                                raw_section: RawSection::Section32(section),
-                                input: self.input,
-                            })
-                        }
-                    }
-                }
-                RawHeader::MachHeader64(h) => {
-                    let h: *mut getsect::mach_header_64 = mem::transmute(h);
-                    let section = if self.magic() == loader::MH_MAGIC_64 {
-                        getsect::getsectbynamefromheader_64(h,
-                                                            segment_name.as_ptr(),
-                                                            section_name.as_ptr())
-                    } else {
-                        assert_eq!(self.magic(), loader::MH_CIGAM_64);
-                        let section =
-                            getsect::getsectbynamefromheaderwithswap_64(h,
-                                                                        segment_name.as_ptr(),
-                                                                        section_name.as_ptr(),
-                                                                        1);
-                        mem::transmute(section)
-                    };
-
-                    match section.as_ref() {
-                        None => None,
-                        Some(section) => {
-                            Some(Section {
-                                raw_section: RawSection::Section64(section),
-                                input: self.input,
-                            })
-                        }
-                    }
-                }
-            }
-        }
+    /// Get this header's CPU type.
+    pub fn cputype(&self) -> CpuType {
+        CpuType::from_raw(self.read_u32(4) as i32)
     }
-}
 
-#[derive(Copy, Clone, Debug)]
-enum RawSection {
-    Section32(*const getsect::section),
-    Section64(*const getsect::section_64),
-}
+    /// Get this header's CPU subtype. This is architecture-specific, and is
+    /// returned as the raw `cpusubtype` value.
+    pub fn cpusubtype(&self) -> i32 {
+        self.read_u32(8) as i32
+    }
 
-/// A section in the mach-o file.
-#[derive(Copy, Clone, Debug)]
-pub struct Section<'a> {
-    raw_section: RawSection,
-    input: &'a [u8],
-}
+    /// Get this header's file type.
+    pub fn filetype(&self) -> FileType {
+        FileType::from_raw(self.read_u32(12))
+    }
 
-impl<'a> Section<'a> {
-    /// Get this section's name.
-    pub fn name(&self) -> &CStr {
-        unsafe {
-            match self.raw_section {
-                RawSection::Section32(s) => {
-                    CStr::from_ptr(mem::transmute(&s.as_ref().unwrap().sectname))
-                }
-                RawSection::Section64(s) => {
-                    CStr::from_ptr(mem::transmute(&s.as_ref().unwrap().sectname))
-                }
-            }
-        }
+    /// Get the number of load commands following this header.
+    pub fn ncmds(&self) -> u32 {
+        // `ncmds` is the fifth `u32` in both `mach_header` and
+        // `mach_header_64`, which share their first seven fields.
+        self.read_u32(16)
     }
 
-    /// Get this section's segment's name.
-    pub fn segment_name(&self) -> &CStr {
-        unsafe {
-            match self.raw_section {
-                RawSection::Section32(s) => {
-                    CStr::from_ptr(mem::transmute(&s.as_ref().unwrap().segname))
-                }
-                RawSection::Section64(s) => {
-                    CStr::from_ptr(mem::transmute(&s.as_ref().unwrap().segname))
+    /// Get the size, in bytes, of the load commands following this header.
+    pub fn sizeofcmds(&self) -> u32 {
+        self.read_u32(20)
+    }
+
+    /// Get this header's flags.
+    pub fn flags(&self) -> Flags {
+        Flags::from_bits_truncate(self.read_u32(24))
+    }
+
+    /// Iterate over this header's load commands.
+    pub fn load_commands(&self) -> LoadCommands<'a> {
+        let start = self.header_size();
+        let end = start + self.sizeofcmds() as usize;
+        let end = if end > self.input.len() { self.input.len() } else { end };
+        LoadCommands::new(self.input, start, end, self.ncmds(), self.swap)
+    }
+
+    /// Iterate over this header's `LC_SEGMENT`/`LC_SEGMENT_64` load commands.
+    pub fn segments(&self) -> Segments<'a> {
+        Segments::new(self.load_commands())
+    }
+
+    /// Get the data for a given section, if it exists.
+    pub fn get_section(&self, segment_name: &CStr, section_name: &CStr) -> Option<Section<'a>> {
+        for segment in self.segments() {
+            if segment.name() == segment_name {
+                for section in segment.sections() {
+                    if section.name() == section_name {
+                        return Some(section);
+                    }
                 }
             }
         }
+        None
     }
 
-    /// Get this section's vm address.
-    pub fn addr(&self) -> u64 {
-        unsafe {
-            match self.raw_section {
-                RawSection::Section32(s) => s.as_ref().unwrap().addr as u64,
-                RawSection::Section64(s) => s.as_ref().unwrap().addr,
-            }
+    fn is_64(&self) -> bool {
+        match self.bitness {
+            Bitness::Bits32 => false,
+            Bitness::Bits64 => true,
         }
     }
 
-    /// Get this section's data.
-    pub fn data(&self) -> &'a [u8] {
-        unsafe {
-            match self.raw_section {
-                RawSection::Section32(s) => {
-                    let s = s.as_ref().unwrap();
-                    let start = s.offset as usize;
-                    let end = start + s.size as usize;
-                    &self.input[start..end]
-                }
-                RawSection::Section64(s) => {
-                    let s = s.as_ref().unwrap();
-                    let start = s.offset as usize;
-                    let end = start + s.size as usize;
-                    &self.input[start..end]
+    /// Iterate over the symbols in this header's `LC_SYMTAB` symbol table, if
+    /// it has one.
+    pub fn symbols(&self) -> Symbols<'a> {
+        for command in self.load_commands() {
+            if let LoadCommand::Other { cmd, data } = command {
+                if cmd == LC_SYMTAB {
+                    let symoff = read_u32(&data[8..12], self.swap) as usize;
+                    let nsyms = read_u32(&data[12..16], self.swap);
+                    let stroff = read_u32(&data[16..20], self.swap) as usize;
+                    let strsize = read_u32(&data[20..24], self.swap) as usize;
+                    return Symbols::new(self.input, symoff, nsyms, stroff, strsize, self.is_64(), self.swap);
                 }
             }
         }
+        Symbols::empty(self.input)
     }
 }
 
@@ -226,4 +390,35 @@ mod tests {
         let header = Header::new(buf).expect("Should parse the header OK");
         assert_eq!(header.magic(), loader::MH_MAGIC_64);
     }
+
+    #[test]
+    fn test_header_accessors() {
+        let header = Header::new(&LITTLE_ENDIAN_HEADER_64).expect("Should parse the header OK");
+        assert_eq!(header.cputype(), CpuType::X86_64);
+        assert_eq!(header.cpusubtype(), 0x80000003u32 as i32);
+        assert_eq!(header.filetype(), FileType::Execute);
+        assert_eq!(header.ncmds(), 18);
+        assert_eq!(header.sizeofcmds(), 2264);
+        assert!(header.flags().contains(Flags::NOUNDEFS));
+    }
+
+    // A big-endian (`MH_CIGAM`) 32-bit header: its multi-byte fields are
+    // stored in the non-native byte order, so every accessor above must
+    // byte-swap them back.
+    const BIG_ENDIAN_HEADER_32: [u8; 28] = [0xfe, 0xed, 0xfa, 0xce, 0x00, 0x00, 0x00, 0x0c, 0x00,
+                                             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00,
+                                             0x00, 0x03, 0x00, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00,
+                                             0x01];
+
+    #[test]
+    fn test_read_swapped_header() {
+        let header = Header::new(&BIG_ENDIAN_HEADER_32).expect("Should parse the header OK");
+        assert_eq!(header.magic(), loader::MH_MAGIC);
+        assert_eq!(header.cputype(), CpuType::Arm);
+        assert_eq!(header.cpusubtype(), 0);
+        assert_eq!(header.filetype(), FileType::Dylib);
+        assert_eq!(header.ncmds(), 3);
+        assert_eq!(header.sizeofcmds(), 100);
+        assert!(header.flags().contains(Flags::NOUNDEFS));
+    }
 }
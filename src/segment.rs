@@ -0,0 +1,553 @@
+//! Pure-Rust iteration over a mach-o file's load commands, segments, and
+//! sections, without going through the `getsect` FFI. This means it works on
+//! any host, not just OSX.
+
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::io::{self, Read};
+use std::mem;
+
+use flate2::read::ZlibDecoder;
+use mach_o_sys::loader;
+
+use {read_u32, read_u64};
+
+// The `ZLIB` magic that, in-band, marks a section as zlib-compressed: a
+// 4-byte magic followed by an 8-byte big-endian uncompressed size.
+const ZLIB_MAGIC: &[u8] = b"ZLIB";
+const ZLIB_HEADER_SIZE: usize = 12;
+
+fn read_u64_be(bytes: &[u8]) -> u64 {
+    let mut value = 0u64;
+    for &byte in bytes {
+        value = (value << 8) | byte as u64;
+    }
+    value
+}
+
+const LC_SEGMENT: u32 = loader::LC_SEGMENT as u32;
+const LC_SEGMENT_64: u32 = loader::LC_SEGMENT_64 as u32;
+
+// Find the NUL terminator within a fixed 16-byte `segname`/`sectname` field
+// and hand back the `&CStr` up to and including it.
+fn cstr16(bytes: &[u8]) -> &CStr {
+    let len = bytes.iter()
+        .position(|&b| b == 0)
+        .expect("segment/section names are NUL-terminated within their 16-byte field");
+    CStr::from_bytes_with_nul(&bytes[..len + 1]).unwrap()
+}
+
+/// A parsed load command.
+#[derive(Copy, Clone, Debug)]
+pub enum LoadCommand<'a> {
+    /// An `LC_SEGMENT` or `LC_SEGMENT_64` load command.
+    Segment(Segment<'a>),
+    /// A load command this crate doesn't parse any further yet.
+    Other {
+        /// This load command's `cmd` field.
+        cmd: u32,
+        /// The full contents of this load command, including its `cmd` and
+        /// `cmdsize` header.
+        data: &'a [u8],
+    },
+}
+
+/// An iterator over a header's load commands, see `Header::load_commands`.
+#[derive(Copy, Clone, Debug)]
+pub struct LoadCommands<'a> {
+    input: &'a [u8],
+    offset: usize,
+    limit: usize,
+    remaining: u32,
+    swap: bool,
+}
+
+impl<'a> LoadCommands<'a> {
+    pub(crate) fn new(input: &'a [u8],
+                       offset: usize,
+                       limit: usize,
+                       ncmds: u32,
+                       swap: bool)
+                       -> LoadCommands<'a> {
+        LoadCommands {
+            input: input,
+            offset: offset,
+            limit: limit,
+            remaining: ncmds,
+            swap: swap,
+        }
+    }
+}
+
+impl<'a> Iterator for LoadCommands<'a> {
+    type Item = LoadCommand<'a>;
+
+    fn next(&mut self) -> Option<LoadCommand<'a>> {
+        if self.remaining == 0 || self.offset + 8 > self.limit || self.offset + 8 > self.input.len() {
+            return None;
+        }
+
+        let cmd = read_u32(&self.input[self.offset..self.offset + 4], self.swap);
+        let cmdsize = read_u32(&self.input[self.offset + 4..self.offset + 8], self.swap) as usize;
+
+        if cmdsize < 8 || self.offset + cmdsize > self.input.len() {
+            return None;
+        }
+
+        let data = &self.input[self.offset..self.offset + cmdsize];
+
+        // A segment command that claims a `cmdsize` too small to hold its own
+        // fixed-size fields is corrupt; fall back to `Other` rather than
+        // parsing it. Section records past the end of the file are caught
+        // later, by `Sections`.
+        let command = match cmd {
+            LC_SEGMENT => {
+                parse_segment(self.input, self.offset, cmdsize, false, self.swap)
+                    .map(LoadCommand::Segment)
+                    .unwrap_or(LoadCommand::Other { cmd: cmd, data: data })
+            }
+            LC_SEGMENT_64 => {
+                parse_segment(self.input, self.offset, cmdsize, true, self.swap)
+                    .map(LoadCommand::Segment)
+                    .unwrap_or(LoadCommand::Other { cmd: cmd, data: data })
+            }
+            _ => LoadCommand::Other { cmd: cmd, data: data },
+        };
+
+        self.offset += cmdsize;
+        self.remaining -= 1;
+
+        Some(command)
+    }
+}
+
+// Returns `None` if the command's `cmdsize` is too small to hold a full
+// `segment_command`, or if `offset + header_size` runs past `input`.
+fn parse_segment<'a>(input: &'a [u8],
+                     offset: usize,
+                     cmdsize: usize,
+                     is_64: bool,
+                     swap: bool)
+                     -> Option<Segment<'a>> {
+    let (header_size, vmaddr_off, vmsize_off, fileoff_off, filesize_off, nsects_off) = if is_64 {
+        (mem::size_of::<loader::segment_command_64>(), 24, 32, 40, 48, 64)
+    } else {
+        (mem::size_of::<loader::segment_command>(), 24, 28, 32, 36, 48)
+    };
+
+    if cmdsize < header_size || offset + header_size > input.len() {
+        return None;
+    }
+
+    let record = &input[offset..offset + header_size];
+    let segname = &record[8..24];
+
+    let (vmaddr, vmsize, fileoff, filesize) = if is_64 {
+        (read_u64(&record[vmaddr_off..vmaddr_off + 8], swap),
+         read_u64(&record[vmsize_off..vmsize_off + 8], swap),
+         read_u64(&record[fileoff_off..fileoff_off + 8], swap),
+         read_u64(&record[filesize_off..filesize_off + 8], swap))
+    } else {
+        (read_u32(&record[vmaddr_off..vmaddr_off + 4], swap) as u64,
+         read_u32(&record[vmsize_off..vmsize_off + 4], swap) as u64,
+         read_u32(&record[fileoff_off..fileoff_off + 4], swap) as u64,
+         read_u32(&record[filesize_off..filesize_off + 4], swap) as u64)
+    };
+
+    let nsects = read_u32(&record[nsects_off..nsects_off + 4], swap);
+
+    Some(Segment {
+        input: input,
+        segname: segname,
+        vmaddr: vmaddr,
+        vmsize: vmsize,
+        fileoff: fileoff,
+        filesize: filesize,
+        sections_offset: offset + header_size,
+        nsects: nsects,
+        is_64: is_64,
+        swap: swap,
+    })
+}
+
+/// A segment and its sections, from an `LC_SEGMENT`/`LC_SEGMENT_64` load
+/// command.
+#[derive(Copy, Clone, Debug)]
+pub struct Segment<'a> {
+    input: &'a [u8],
+    segname: &'a [u8],
+    vmaddr: u64,
+    vmsize: u64,
+    fileoff: u64,
+    filesize: u64,
+    sections_offset: usize,
+    nsects: u32,
+    is_64: bool,
+    swap: bool,
+}
+
+impl<'a> Segment<'a> {
+    /// This segment's name, e.g. `__TEXT`.
+    pub fn name(&self) -> &CStr {
+        cstr16(self.segname)
+    }
+
+    /// This segment's preferred virtual memory address.
+    pub fn vmaddr(&self) -> u64 {
+        self.vmaddr
+    }
+
+    /// This segment's size in virtual memory.
+    pub fn vmsize(&self) -> u64 {
+        self.vmsize
+    }
+
+    /// This segment's offset within the file.
+    pub fn fileoff(&self) -> u64 {
+        self.fileoff
+    }
+
+    /// This segment's size within the file.
+    pub fn filesize(&self) -> u64 {
+        self.filesize
+    }
+
+    /// Iterate over this segment's sections.
+    pub fn sections(&self) -> Sections<'a> {
+        Sections {
+            input: self.input,
+            offset: self.sections_offset,
+            remaining: self.nsects,
+            is_64: self.is_64,
+            swap: self.swap,
+        }
+    }
+}
+
+/// An iterator over a segment's sections, see `Segment::sections`.
+#[derive(Copy, Clone, Debug)]
+pub struct Sections<'a> {
+    input: &'a [u8],
+    offset: usize,
+    remaining: u32,
+    is_64: bool,
+    swap: bool,
+}
+
+impl<'a> Iterator for Sections<'a> {
+    type Item = Section<'a>;
+
+    fn next(&mut self) -> Option<Section<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let record_size = if self.is_64 {
+            mem::size_of::<loader::section_64>()
+        } else {
+            mem::size_of::<loader::section>()
+        };
+
+        // A segment that lied about its `nsects` can claim more section
+        // records than actually fit after it; stop rather than read past
+        // the end of the file.
+        if self.offset + record_size > self.input.len() {
+            self.remaining = 0;
+            return None;
+        }
+
+        let record = &self.input[self.offset..self.offset + record_size];
+
+        let sectname = &record[0..16];
+        let segname = &record[16..32];
+        let (addr, size, file_offset) = if self.is_64 {
+            (read_u64(&record[32..40], self.swap),
+             read_u64(&record[40..48], self.swap),
+             read_u32(&record[48..52], self.swap))
+        } else {
+            (read_u32(&record[32..36], self.swap) as u64,
+             read_u32(&record[36..40], self.swap) as u64,
+             read_u32(&record[40..44], self.swap))
+        };
+
+        self.offset += record_size;
+        self.remaining -= 1;
+
+        Some(Section {
+            input: self.input,
+            sectname: sectname,
+            segname: segname,
+            addr: addr,
+            size: size,
+            offset: file_offset,
+        })
+    }
+}
+
+/// A section in the mach-o file.
+#[derive(Copy, Clone, Debug)]
+pub struct Section<'a> {
+    input: &'a [u8],
+    sectname: &'a [u8],
+    segname: &'a [u8],
+    addr: u64,
+    size: u64,
+    offset: u32,
+}
+
+impl<'a> Section<'a> {
+    /// Get this section's name.
+    pub fn name(&self) -> &CStr {
+        cstr16(self.sectname)
+    }
+
+    /// Get this section's segment's name.
+    pub fn segment_name(&self) -> &CStr {
+        cstr16(self.segname)
+    }
+
+    /// Get this section's vm address.
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Get this section's data, or an empty slice if this section's
+    /// `offset`/`size` don't actually fit within the file, e.g. because of a
+    /// corrupt or truncated file.
+    pub fn data(&self) -> &'a [u8] {
+        let start = self.offset as usize;
+        start.checked_add(self.size as usize)
+            .and_then(|end| self.input.get(start..end))
+            .unwrap_or(&[])
+    }
+
+    /// Get this section's raw data together with the compression format and
+    /// uncompressed size read from its in-band compression header, if it has
+    /// one. The data itself is not decompressed; see `uncompressed_data`.
+    pub fn compressed_data(&self) -> CompressedData<'a> {
+        let data = self.data();
+        if data.len() >= ZLIB_HEADER_SIZE && &data[0..4] == ZLIB_MAGIC {
+            CompressedData {
+                format: CompressionFormat::Zlib,
+                uncompressed_size: read_u64_be(&data[4..12]),
+                data: &data[ZLIB_HEADER_SIZE..],
+            }
+        } else {
+            CompressedData {
+                format: CompressionFormat::None,
+                uncompressed_size: data.len() as u64,
+                data: data,
+            }
+        }
+    }
+
+    /// Get this section's data, inflating it first if it's compressed.
+    /// Returns `data()` unchanged, without copying, when the section isn't
+    /// compressed. Returns an error if the section claims to be
+    /// zlib-compressed but isn't a valid, complete deflate stream, rather
+    /// than panicking on a corrupt or truncated file.
+    pub fn uncompressed_data(&self) -> io::Result<Cow<'a, [u8]>> {
+        let compressed = self.compressed_data();
+        match compressed.format {
+            CompressionFormat::None => Ok(Cow::Borrowed(compressed.data)),
+            CompressionFormat::Zlib => {
+                let mut out = Vec::with_capacity(compressed.uncompressed_size as usize);
+                ZlibDecoder::new(compressed.data).read_to_end(&mut out)?;
+                Ok(Cow::Owned(out))
+            }
+        }
+    }
+}
+
+/// The compression format a section's data is stored in, see
+/// `Section::compressed_data`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CompressionFormat {
+    /// Not compressed; the section's bytes are its uncompressed data.
+    None,
+    /// zlib-compressed, with an in-band `ZLIB` magic and 8-byte big-endian
+    /// uncompressed size preceding the compressed stream.
+    Zlib,
+}
+
+/// A section's data together with the compression format it's stored in,
+/// see `Section::compressed_data`.
+#[derive(Copy, Clone, Debug)]
+pub struct CompressedData<'a> {
+    /// Which compression format `data` is stored in.
+    pub format: CompressionFormat,
+    /// The data's uncompressed size in bytes, taken from the in-band header
+    /// when compressed, or `data`'s own length otherwise.
+    pub uncompressed_size: u64,
+    /// The raw bytes: the compressed stream, past the `ZLIB` header, when
+    /// `format` isn't `None`; otherwise the same as `Section::data()`.
+    pub data: &'a [u8],
+}
+
+/// An iterator over a header's `LC_SEGMENT`/`LC_SEGMENT_64` load commands,
+/// see `Header::segments`.
+#[derive(Copy, Clone, Debug)]
+pub struct Segments<'a> {
+    commands: LoadCommands<'a>,
+}
+
+impl<'a> Segments<'a> {
+    pub(crate) fn new(commands: LoadCommands<'a>) -> Segments<'a> {
+        Segments { commands: commands }
+    }
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment<'a>;
+
+    fn next(&mut self) -> Option<Segment<'a>> {
+        for command in &mut self.commands {
+            if let LoadCommand::Segment(segment) = command {
+                return Some(segment);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use write::Builder;
+    use {FileType, Header};
+
+    #[test]
+    fn test_segments_and_sections() {
+        let mut builder = Builder::new(7, 3, FileType::Object);
+        builder.segment("__TEXT").section("__text", vec![0xc3, 0xc3]);
+        let data = builder.build();
+
+        let header = Header::new(&data).expect("Should parse the header OK");
+        let segments: Vec<_> = header.segments().collect();
+        assert_eq!(segments.len(), 1);
+
+        let segment = &segments[0];
+        assert_eq!(segment.name(), CString::new("__TEXT").unwrap().as_c_str());
+
+        let sections: Vec<_> = segment.sections().collect();
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].name(), CString::new("__text").unwrap().as_c_str());
+        assert_eq!(sections[0].data(), &[0xc3, 0xc3]);
+    }
+
+    #[test]
+    fn test_truncated_section_data_does_not_panic() {
+        let mut builder = Builder::new(7, 3, FileType::Object);
+        builder.segment("__TEXT").section("__text", vec![0xc3, 0xc3]);
+        let mut data = builder.build();
+
+        // Truncate the file so the one section's `offset`/`size` no longer
+        // fit within it.
+        let len = data.len();
+        data.truncate(len - 2);
+
+        let header = Header::new(&data).expect("Should parse the header OK");
+        let section = header.segments().next().unwrap().sections().next().unwrap();
+        assert_eq!(section.data(), &[] as &[u8]);
+    }
+
+    #[test]
+    fn test_truncated_segment_command_does_not_panic() {
+        let mut builder = Builder::new(7, 3, FileType::Object);
+        builder.segment("__TEXT");
+        let mut data = builder.build();
+
+        // Claim a `cmdsize` too small to hold a `segment_command`, and
+        // truncate the file to match, mirroring a corrupt or truncated
+        // `LC_SEGMENT`.
+        data[32] = 8;
+        data.truncate(36);
+
+        let header = Header::new(&data).expect("Should parse the header OK");
+        let commands: Vec<_> = header.load_commands().collect();
+        assert_eq!(commands.len(), 1);
+        match commands[0] {
+            LoadCommand::Other { cmd, data } => {
+                assert_eq!(cmd, LC_SEGMENT);
+                assert_eq!(data.len(), 8);
+            }
+            LoadCommand::Segment(_) => panic!("expected a corrupt LC_SEGMENT to fall back to Other"),
+        }
+    }
+
+    #[test]
+    fn test_segment_lying_about_nsects_does_not_panic() {
+        let mut builder = Builder::new(7, 3, FileType::Object);
+        builder.segment("__TEXT").section("__text", vec![0xc3, 0xc3]);
+        let mut data = builder.build();
+
+        // Shrink `cmdsize` to cover only the segment_command header (so
+        // `LoadCommands` still accepts the command), but leave `nsects` at 1
+        // and truncate the file right after the header, so the section
+        // record it claims to have doesn't actually fit.
+        data[32] = 56;
+        data.truncate(84);
+
+        let header = Header::new(&data).expect("Should parse the header OK");
+        let segments: Vec<_> = header.segments().collect();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].sections().count(), 0);
+    }
+
+    fn zlib_section_data(uncompressed: &[u8]) -> Vec<u8> {
+        use flate2::Compression;
+        use flate2::write::ZlibEncoder;
+        use std::io::Write;
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(uncompressed).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut data = Vec::new();
+        data.extend_from_slice(ZLIB_MAGIC);
+        data.extend_from_slice(&(uncompressed.len() as u64).to_be_bytes());
+        data.extend_from_slice(&compressed);
+        data
+    }
+
+    #[test]
+    fn test_uncompressed_data_passthrough() {
+        let mut builder = Builder::new(7, 3, FileType::Object);
+        builder.segment("__TEXT").section("__text", vec![0xc3, 0xc3]);
+        let data = builder.build();
+
+        let header = Header::new(&data).expect("Should parse the header OK");
+        let section = header.segments().next().unwrap().sections().next().unwrap();
+        assert_eq!(&*section.uncompressed_data().unwrap(), &[0xc3, 0xc3]);
+    }
+
+    #[test]
+    fn test_uncompressed_data_inflates_zlib_section() {
+        let uncompressed = b"some debug info worth compressing";
+        let mut builder = Builder::new(7, 3, FileType::Object);
+        builder.segment("__DWARF").section("__debug_info", zlib_section_data(uncompressed));
+        let data = builder.build();
+
+        let header = Header::new(&data).expect("Should parse the header OK");
+        let section = header.segments().next().unwrap().sections().next().unwrap();
+        assert_eq!(section.compressed_data().format, CompressionFormat::Zlib);
+        assert_eq!(&*section.uncompressed_data().unwrap(), &uncompressed[..]);
+    }
+
+    #[test]
+    fn test_uncompressed_data_corrupt_zlib_returns_err() {
+        let mut garbage = Vec::new();
+        garbage.extend_from_slice(ZLIB_MAGIC);
+        garbage.extend_from_slice(&[0u8; 8]); // uncompressed size
+        garbage.extend_from_slice(&[0xff; 16]); // not a valid deflate stream
+
+        let mut builder = Builder::new(7, 3, FileType::Object);
+        builder.segment("__DWARF").section("__debug_info", garbage);
+        let data = builder.build();
+
+        let header = Header::new(&data).expect("Should parse the header OK");
+        let section = header.segments().next().unwrap().sections().next().unwrap();
+        assert!(section.uncompressed_data().is_err());
+    }
+}
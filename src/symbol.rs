@@ -0,0 +1,221 @@
+//! Symbol table access, by parsing the `LC_SYMTAB` load command and its
+//! `nlist`/`nlist_64` entries.
+
+use mach_o_sys::nlist;
+use std::ffi::CStr;
+
+use {read_u32, read_u64};
+
+const N_TYPE: u8 = nlist::N_TYPE;
+const N_EXT: u8 = nlist::N_EXT;
+const N_UNDF: u8 = nlist::N_UNDF;
+const N_ABS: u8 = nlist::N_ABS;
+const N_SECT: u8 = nlist::N_SECT;
+const N_PBUD: u8 = nlist::N_PBUD;
+const N_INDR: u8 = nlist::N_INDR;
+
+/// What a symbol refers to, decoded from the `N_TYPE` bits of its `n_type`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SymbolType {
+    /// An undefined symbol, to be resolved by the dynamic linker.
+    Undefined,
+    /// An absolute symbol, not relative to any section.
+    Absolute,
+    /// A symbol defined in one of this file's sections.
+    Section,
+    /// A prebound undefined symbol.
+    Prebound,
+    /// An indirect symbol, whose real name is another symbol's string.
+    Indirect,
+}
+
+/// A single entry from a mach-o symbol table.
+#[derive(Copy, Clone, Debug)]
+pub struct Symbol<'a> {
+    strtab: &'a [u8],
+    n_strx: u32,
+    n_type: u8,
+    n_value: u64,
+}
+
+impl<'a> Symbol<'a> {
+    /// This symbol's name, or `None` if it has no name (`n_strx == 0`) or
+    /// `n_strx` doesn't point within the string table.
+    pub fn name(&self) -> Option<&'a CStr> {
+        if self.n_strx == 0 {
+            return None;
+        }
+
+        let bytes = self.strtab.get(self.n_strx as usize..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        Some(CStr::from_bytes_with_nul(&bytes[..len + 1]).unwrap())
+    }
+
+    /// Whether this is an externally visible (`N_EXT`) symbol.
+    pub fn is_external(&self) -> bool {
+        self.n_type & N_EXT != 0
+    }
+
+    /// What this symbol refers to, decoded from the `N_TYPE` bits of
+    /// `n_type`.
+    pub fn symbol_type(&self) -> SymbolType {
+        match self.n_type & N_TYPE {
+            N_UNDF => SymbolType::Undefined,
+            N_ABS => SymbolType::Absolute,
+            N_SECT => SymbolType::Section,
+            N_PBUD => SymbolType::Prebound,
+            N_INDR => SymbolType::Indirect,
+            // The remaining `N_TYPE` bit patterns are reserved.
+            _ => SymbolType::Undefined,
+        }
+    }
+
+    /// This symbol's value: typically a virtual memory address, though its
+    /// exact meaning depends on `symbol_type()`.
+    pub fn value(&self) -> u64 {
+        self.n_value
+    }
+}
+
+/// An iterator over a header's symbol table, see `Header::symbols`.
+#[derive(Copy, Clone, Debug)]
+pub struct Symbols<'a> {
+    input: &'a [u8],
+    offset: usize,
+    remaining: u32,
+    is_64: bool,
+    swap: bool,
+    strtab: &'a [u8],
+}
+
+impl<'a> Symbols<'a> {
+    // Returns an empty `Symbols` if the `LC_SYMTAB` fields don't describe a
+    // string table that actually fits within `input` — a corrupt or
+    // truncated file shouldn't panic the caller.
+    pub(crate) fn new(input: &'a [u8],
+                       symoff: usize,
+                       nsyms: u32,
+                       stroff: usize,
+                       strsize: usize,
+                       is_64: bool,
+                       swap: bool)
+                       -> Symbols<'a> {
+        let strtab = match input.get(stroff..stroff + strsize) {
+            Some(strtab) => strtab,
+            None => return Symbols::empty(input),
+        };
+
+        Symbols {
+            input: input,
+            offset: symoff,
+            remaining: nsyms,
+            is_64: is_64,
+            swap: swap,
+            strtab: strtab,
+        }
+    }
+
+    pub(crate) fn empty(input: &'a [u8]) -> Symbols<'a> {
+        Symbols {
+            input: input,
+            offset: 0,
+            remaining: 0,
+            is_64: false,
+            swap: false,
+            strtab: &input[0..0],
+        }
+    }
+}
+
+impl<'a> Iterator for Symbols<'a> {
+    type Item = Symbol<'a>;
+
+    fn next(&mut self) -> Option<Symbol<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let record_size = if self.is_64 { 16 } else { 12 };
+
+        // `nsyms` is as untrusted as everything else in `LC_SYMTAB`; stop
+        // rather than read a record that runs past the end of the file.
+        if self.offset + record_size > self.input.len() {
+            self.remaining = 0;
+            return None;
+        }
+
+        let record = &self.input[self.offset..self.offset + record_size];
+
+        let n_strx = read_u32(&record[0..4], self.swap);
+        let n_type = record[4];
+        let n_value = if self.is_64 {
+            read_u64(&record[8..16], self.swap)
+        } else {
+            read_u32(&record[8..12], self.swap) as u64
+        };
+
+        self.offset += record_size;
+        self.remaining -= 1;
+
+        Some(Symbol {
+            strtab: self.strtab,
+            n_strx: n_strx,
+            n_type: n_type,
+            n_value: n_value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One 32-bit `nlist` record (n_strx=1, n_type=N_EXT, n_sect=0, n_desc=0,
+    // n_value=0x12345678), followed by its string table ("\0abc\0").
+    const ONE_SYMBOL: [u8; 12 + 5] = [0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x78, 0x56,
+                                       0x34, 0x12, 0x00, b'a', b'b', b'c', 0x00];
+
+    #[test]
+    fn test_symbols_iteration() {
+        let symbols = Symbols::new(&ONE_SYMBOL, 0, 1, 12, 5, false, false);
+        let symbols: Vec<_> = symbols.collect();
+        assert_eq!(symbols.len(), 1);
+
+        let symbol = &symbols[0];
+        assert_eq!(symbol.name().unwrap().to_bytes(), b"abc");
+        assert!(symbol.is_external());
+        assert_eq!(symbol.symbol_type(), SymbolType::Undefined);
+        assert_eq!(symbol.value(), 0x12345678);
+    }
+
+    #[test]
+    fn test_name_with_no_strx_is_none() {
+        let symbols = Symbols::new(&ONE_SYMBOL, 0, 1, 12, 5, false, false);
+        let mut symbol = symbols.collect::<Vec<_>>().remove(0);
+        symbol.n_strx = 0;
+        assert_eq!(symbol.name(), None);
+    }
+
+    #[test]
+    fn test_out_of_range_strx_does_not_panic() {
+        let symbols = Symbols::new(&ONE_SYMBOL, 0, 1, 12, 5, false, false);
+        let mut symbol = symbols.collect::<Vec<_>>().remove(0);
+        symbol.n_strx = 1000;
+        assert_eq!(symbol.name(), None);
+    }
+
+    #[test]
+    fn test_corrupt_symtab_fields_return_empty() {
+        // `stroff`/`strsize` describe a string table well past the end of
+        // the input.
+        let symbols = Symbols::new(&ONE_SYMBOL, 0, 1, 2000, 100, false, false);
+        assert_eq!(symbols.collect::<Vec<_>>().len(), 0);
+    }
+
+    #[test]
+    fn test_nsyms_lying_about_remaining_records_does_not_panic() {
+        // `nsyms` claims more records than actually fit after `symoff`.
+        let symbols = Symbols::new(&ONE_SYMBOL, 0, 2, 12, 5, false, false);
+        assert_eq!(symbols.collect::<Vec<_>>().len(), 1);
+    }
+}
@@ -0,0 +1,296 @@
+//! Building mach-o object files from scratch. This is the write-side
+//! counterpart to the rest of the crate, which only reads existing files.
+
+use mach_o_sys::loader;
+
+use {FileType, Flags, CPU_ARCH_ABI64};
+
+const LC_SEGMENT: u32 = loader::LC_SEGMENT as u32;
+const LC_SEGMENT_64: u32 = loader::LC_SEGMENT_64 as u32;
+
+// `mach_o_sys` predates `LC_BUILD_VERSION` (added for the Xcode 10 SDK), so
+// it isn't bound there; define it, and the `PLATFORM_*` value we emit,
+// ourselves.
+const LC_BUILD_VERSION: u32 = 0x32;
+
+/// The `platform` value for macOS, for use with `Builder::build_version`.
+pub const PLATFORM_MACOS: u32 = 1;
+
+// Full read/write/execute; `mach_o_sys` doesn't bind the `VM_PROT_*` bits.
+const VM_PROT_ALL: i32 = 0x7;
+
+const SEGMENT_COMMAND_SIZE: u32 = 56;
+const SEGMENT_COMMAND_64_SIZE: u32 = 72;
+const SECTION_SIZE: u32 = 68;
+const SECTION_64_SIZE: u32 = 80;
+const BUILD_VERSION_COMMAND_SIZE: u32 = 24;
+
+/// Encode an `X.Y.Z` version as the nibble-packed `xxxx.yy.zz` value used by
+/// the `minos`/`sdk` fields of an `LC_BUILD_VERSION` load command.
+pub fn encode_version(major: u16, minor: u8, patch: u8) -> u32 {
+    ((major as u32) << 16) | ((minor as u32) << 8) | (patch as u32)
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn push_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn push_i32(out: &mut Vec<u8>, value: i32) {
+    push_u32(out, value as u32);
+}
+
+// Write `name` into a fixed 16-byte `segname`/`sectname` field, NUL-padded.
+// `name` must be shorter than 16 bytes so a NUL terminator still fits, since
+// readers (including this crate's own `segment` module) look for one.
+fn push_name16(out: &mut Vec<u8>, name: &str) {
+    let bytes = name.as_bytes();
+    assert!(bytes.len() < 16,
+            "segment/section names must be shorter than 16 bytes");
+    let mut field = [0u8; 16];
+    field[..bytes.len()].copy_from_slice(bytes);
+    out.extend_from_slice(&field);
+}
+
+struct BuildVersion {
+    platform: u32,
+    minos: u32,
+    sdk: u32,
+}
+
+struct SectionBuilder {
+    name: String,
+    data: Vec<u8>,
+}
+
+/// A segment under construction, see `Builder::segment`.
+pub struct SegmentBuilder {
+    name: String,
+    sections: Vec<SectionBuilder>,
+}
+
+impl SegmentBuilder {
+    /// Append a section named `name` containing `data`.
+    pub fn section(&mut self, name: &str, data: Vec<u8>) -> &mut SegmentBuilder {
+        self.sections.push(SectionBuilder {
+            name: name.to_string(),
+            data: data,
+        });
+        self
+    }
+}
+
+/// Builds up a mach-o object file in memory.
+pub struct Builder {
+    cputype: i32,
+    cpusubtype: i32,
+    filetype: FileType,
+    flags: Flags,
+    build_version: Option<BuildVersion>,
+    segments: Vec<SegmentBuilder>,
+}
+
+impl Builder {
+    /// Start building a new object file with the given `cputype`,
+    /// `cpusubtype`, and `filetype`. Whether the output is a 32- or 64-bit
+    /// mach-o file is decided by `cputype`'s `CPU_ARCH_ABI64` bit.
+    pub fn new(cputype: i32, cpusubtype: i32, filetype: FileType) -> Builder {
+        Builder {
+            cputype: cputype,
+            cpusubtype: cpusubtype,
+            filetype: filetype,
+            flags: Flags::empty(),
+            build_version: None,
+            segments: Vec::new(),
+        }
+    }
+
+    /// Set the header's flags.
+    pub fn flags(&mut self, flags: Flags) -> &mut Builder {
+        self.flags = flags;
+        self
+    }
+
+    /// Emit an `LC_BUILD_VERSION` load command for `platform`, with `minos`
+    /// and `sdk` encoded via `encode_version`.
+    pub fn build_version(&mut self, platform: u32, minos: u32, sdk: u32) -> &mut Builder {
+        self.build_version = Some(BuildVersion {
+            platform: platform,
+            minos: minos,
+            sdk: sdk,
+        });
+        self
+    }
+
+    /// Append a new, initially empty, segment named `name`.
+    pub fn segment(&mut self, name: &str) -> &mut SegmentBuilder {
+        self.segments.push(SegmentBuilder {
+            name: name.to_string(),
+            sections: Vec::new(),
+        });
+        self.segments.last_mut().unwrap()
+    }
+
+    /// Serialize this builder into a mach-o object file, writing the header
+    /// and all multi-byte load command fields in host byte order so the
+    /// result can be parsed straight back by `Header::new`.
+    pub fn build(&self) -> Vec<u8> {
+        let is_64 = self.cputype & CPU_ARCH_ABI64 != 0;
+
+        let (header_size, segment_cmd_size, section_size) = if is_64 {
+            (32, SEGMENT_COMMAND_64_SIZE, SECTION_64_SIZE)
+        } else {
+            (28, SEGMENT_COMMAND_SIZE, SECTION_SIZE)
+        };
+
+        let mut ncmds = self.segments.len() as u32;
+        let mut sizeofcmds = 0u32;
+        for segment in &self.segments {
+            sizeofcmds += segment_cmd_size + segment.sections.len() as u32 * section_size;
+        }
+        if self.build_version.is_some() {
+            ncmds += 1;
+            sizeofcmds += BUILD_VERSION_COMMAND_SIZE;
+        }
+
+        let mut out = Vec::new();
+
+        push_u32(&mut out,
+                 if is_64 { loader::MH_MAGIC_64 } else { loader::MH_MAGIC });
+        push_i32(&mut out, self.cputype);
+        push_i32(&mut out, self.cpusubtype);
+        push_u32(&mut out, self.filetype.to_raw());
+        push_u32(&mut out, ncmds);
+        push_u32(&mut out, sizeofcmds);
+        push_u32(&mut out, self.flags.bits());
+        if is_64 {
+            push_u32(&mut out, 0); // reserved
+        }
+
+        let mut data_offset = header_size + sizeofcmds as usize;
+        let mut section_data = Vec::new();
+
+        for segment in &self.segments {
+            let filesize: usize = segment.sections.iter().map(|s| s.data.len()).sum();
+            let fileoff = data_offset;
+
+            push_u32(&mut out, if is_64 { LC_SEGMENT_64 } else { LC_SEGMENT });
+            push_u32(&mut out,
+                     segment_cmd_size + segment.sections.len() as u32 * section_size);
+            push_name16(&mut out, &segment.name);
+            if is_64 {
+                push_u64(&mut out, fileoff as u64); // vmaddr
+                push_u64(&mut out, filesize as u64); // vmsize
+                push_u64(&mut out, fileoff as u64); // fileoff
+                push_u64(&mut out, filesize as u64); // filesize
+            } else {
+                push_u32(&mut out, fileoff as u32); // vmaddr
+                push_u32(&mut out, filesize as u32); // vmsize
+                push_u32(&mut out, fileoff as u32); // fileoff
+                push_u32(&mut out, filesize as u32); // filesize
+            }
+            push_i32(&mut out, VM_PROT_ALL); // maxprot
+            push_i32(&mut out, VM_PROT_ALL); // initprot
+            push_u32(&mut out, segment.sections.len() as u32); // nsects
+            push_u32(&mut out, 0); // flags
+
+            let mut section_offset = fileoff;
+            for section in &segment.sections {
+                push_name16(&mut out, &section.name);
+                push_name16(&mut out, &segment.name);
+                if is_64 {
+                    push_u64(&mut out, section_offset as u64); // addr
+                    push_u64(&mut out, section.data.len() as u64); // size
+                } else {
+                    push_u32(&mut out, section_offset as u32); // addr
+                    push_u32(&mut out, section.data.len() as u32); // size
+                }
+                push_u32(&mut out, section_offset as u32); // offset
+                push_u32(&mut out, 0); // align
+                push_u32(&mut out, 0); // reloff
+                push_u32(&mut out, 0); // nreloc
+                push_u32(&mut out, 0); // flags
+                push_u32(&mut out, 0); // reserved1
+                push_u32(&mut out, 0); // reserved2
+                if is_64 {
+                    push_u32(&mut out, 0); // reserved3
+                }
+
+                section_offset += section.data.len();
+                section_data.extend_from_slice(&section.data);
+            }
+
+            data_offset += filesize;
+        }
+
+        if let Some(ref build_version) = self.build_version {
+            push_u32(&mut out, LC_BUILD_VERSION);
+            push_u32(&mut out, BUILD_VERSION_COMMAND_SIZE);
+            push_u32(&mut out, build_version.platform);
+            push_u32(&mut out, build_version.minos);
+            push_u32(&mut out, build_version.sdk);
+            push_u32(&mut out, 0); // ntools
+        }
+
+        out.extend_from_slice(&section_data);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mach_o_sys::loader;
+    use segment::LoadCommand;
+    use {read_u32, CpuType, Header};
+
+    #[test]
+    fn test_build_32_bit() {
+        let data = Builder::new(7, 3, FileType::Object).build();
+        let header = Header::new(&data).expect("Should parse the header OK");
+        assert_eq!(header.magic(), loader::MH_MAGIC);
+        assert_eq!(header.cputype(), CpuType::X86);
+        assert_eq!(header.filetype(), FileType::Object);
+        assert_eq!(header.ncmds(), 0);
+    }
+
+    #[test]
+    fn test_build_64_bit() {
+        let data = Builder::new(7 | CPU_ARCH_ABI64, 3, FileType::Dylib).build();
+        let header = Header::new(&data).expect("Should parse the header OK");
+        assert_eq!(header.magic(), loader::MH_MAGIC_64);
+        assert_eq!(header.cputype(), CpuType::X86_64);
+        assert_eq!(header.filetype(), FileType::Dylib);
+    }
+
+    #[test]
+    fn test_build_version_command() {
+        let minos = encode_version(10, 14, 0);
+        let sdk = encode_version(10, 15, 0);
+
+        let mut builder = Builder::new(7, 3, FileType::Object);
+        builder.build_version(PLATFORM_MACOS, minos, sdk);
+        let data = builder.build();
+
+        let header = Header::new(&data).expect("Should parse the header OK");
+        let commands: Vec<_> = header.load_commands().collect();
+        assert_eq!(commands.len(), 1);
+        match commands[0] {
+            LoadCommand::Other { cmd, data } => {
+                assert_eq!(cmd, LC_BUILD_VERSION);
+                assert_eq!(read_u32(&data[8..12], false), PLATFORM_MACOS);
+                assert_eq!(read_u32(&data[12..16], false), minos);
+                assert_eq!(read_u32(&data[16..20], false), sdk);
+            }
+            LoadCommand::Segment(_) => panic!("LC_BUILD_VERSION should not parse as a segment"),
+        }
+    }
+
+    #[test]
+    fn test_encode_version() {
+        assert_eq!(encode_version(10, 14, 3), 0x000a_0e03);
+    }
+}